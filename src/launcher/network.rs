@@ -0,0 +1,914 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+
+use libc;
+use nix::unistd::{fork, pipe, ForkResult};
+use nix::sys::wait::{waitpid, WaitStatus};
+
+
+// Minimal subset of the rtnetlink ABI.  We used to shell out to the `ip`
+// binary for all of this, but that requires iproute2 to be installed in
+// whatever environment vagga itself runs in, and failures were only
+// visible as unparsed stderr.  Talking to the kernel directly over
+// NETLINK_ROUTE gives us precise errno-based errors and one less runtime
+// dependency.
+
+const NETLINK_ROUTE: libc::c_int = 0;
+
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_ROOT: u16 = 0x100;
+const NLM_F_MATCH: u16 = 0x200;
+const NLM_F_DUMP: u16 = NLM_F_ROOT | NLM_F_MATCH;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_GETLINK: u16 = 18;
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MASTER: u16 = 10;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_NET_NS_FD: u16 = 28;
+const IFLA_INFO_KIND: u16 = 1;
+const IFLA_INFO_DATA: u16 = 2;
+const IFLA_VETH_INFO_PEER: u16 = 1;
+
+const IFF_UP: u32 = 1;
+
+const IFA_LOCAL: u16 = 2;
+const IFA_ADDRESS: u16 = 1;
+
+const RTA_DST: u16 = 1;
+const RTA_GATEWAY: u16 = 5;
+const RTA_OIF: u16 = 4;
+
+const RT_TABLE_MAIN: u8 = 254;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RTPROT_STATIC: u8 = 4;
+const RTN_UNICAST: u8 = 1;
+const AF_INET: u8 = libc::AF_INET as u8;
+
+#[repr(C)]
+struct NlMsgHdr {
+    nlmsg_len: u32,
+    nlmsg_type: u16,
+    nlmsg_flags: u16,
+    nlmsg_seq: u32,
+    nlmsg_pid: u32,
+}
+
+#[repr(C)]
+struct IfInfoMsg {
+    ifi_family: u8,
+    _pad: u8,
+    ifi_type: u16,
+    ifi_index: i32,
+    ifi_flags: u32,
+    ifi_change: u32,
+}
+
+#[repr(C)]
+struct IfAddrMsg {
+    ifa_family: u8,
+    ifa_prefixlen: u8,
+    ifa_flags: u8,
+    ifa_scope: u8,
+    ifa_index: i32,
+}
+
+#[repr(C)]
+struct RtMsg {
+    rtm_family: u8,
+    rtm_dst_len: u8,
+    rtm_src_len: u8,
+    rtm_tos: u8,
+    rtm_table: u8,
+    rtm_protocol: u8,
+    rtm_scope: u8,
+    rtm_type: u8,
+    rtm_flags: u32,
+}
+
+/// A single netlink request being assembled.  Attributes may be nested by
+/// calling `nest_start()`/`nest_end()` around the nested attribute's own
+/// `attr*` calls.
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(msg_type: u16, flags: u16) -> Message {
+        let mut buf = Vec::with_capacity(256);
+        buf.resize(size_of::<NlMsgHdr>(), 0);
+        {
+            let hdr = unsafe { &mut *(buf.as_mut_ptr() as *mut NlMsgHdr) };
+            hdr.nlmsg_len = 0; // fixed up in `finish`
+            hdr.nlmsg_type = msg_type;
+            hdr.nlmsg_flags = NLM_F_REQUEST | flags;
+            hdr.nlmsg_seq = 1;
+            hdr.nlmsg_pid = 0;
+        }
+        Message { buf: buf }
+    }
+
+    fn push<T>(&mut self, val: &T) {
+        let bytes = unsafe {
+            ::std::slice::from_raw_parts(
+                val as *const T as *const u8, size_of::<T>())
+        };
+        self.buf.extend_from_slice(bytes);
+        self.align();
+    }
+
+    fn align(&mut self) {
+        while self.buf.len() % 4 != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn attr(&mut self, attr_type: u16, data: &[u8]) {
+        let len = (4 + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.align();
+    }
+
+    fn attr_str(&mut self, attr_type: u16, val: &str) {
+        let mut data = val.as_bytes().to_vec();
+        data.push(0);
+        self.attr(attr_type, &data);
+    }
+
+    fn attr_u32(&mut self, attr_type: u16, val: u32) {
+        self.attr(attr_type, &val.to_ne_bytes());
+    }
+
+    /// Writes a nested attribute, returning the byte offset its length
+    /// field lives at so the caller can patch it once the nested content
+    /// is known.
+    fn nest_start(&mut self, attr_type: u16) -> usize {
+        let pos = self.buf.len();
+        self.buf.extend_from_slice(&0u16.to_ne_bytes());
+        self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+        pos
+    }
+
+    fn nest_end(&mut self, pos: usize) {
+        let len = (self.buf.len() - pos) as u16;
+        self.buf[pos..pos + 2].copy_from_slice(&len.to_ne_bytes());
+        self.align();
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// Walks a buffer of netlink attributes (TLV: 2-byte length including the
+/// header, 2-byte type, then the payload padded to 4 bytes), returning
+/// each attribute's type and payload.
+fn parse_attrs(data: &[u8]) -> Vec<(u16, Vec<u8>)> {
+    let mut attrs = Vec::new();
+    let mut off = 0usize;
+    while off + 4 <= data.len() {
+        let len = u16::from_ne_bytes([data[off], data[off + 1]]) as usize;
+        let atype = u16::from_ne_bytes([data[off + 2], data[off + 3]]);
+        if len < 4 || off + len > data.len() {
+            break;
+        }
+        attrs.push((atype, data[off + 4..off + len].to_vec()));
+        off += (len + 3) & !3;
+    }
+    attrs
+}
+
+struct NetlinkSocket {
+    fd: RawFd,
+}
+
+impl NetlinkSocket {
+    fn open() -> Result<NetlinkSocket, String> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, NETLINK_ROUTE)
+        };
+        if fd < 0 {
+            return Err(format!("Can't open netlink socket: {}",
+                io::Error::last_os_error()));
+        }
+        Ok(NetlinkSocket { fd: fd })
+    }
+
+    /// Sends `msg` and reads back the kernel's ack, turning a non-zero
+    /// `nlmsgerr.error` into a proper `Result`.
+    fn talk(&self, msg: Vec<u8>) -> Result<(), String> {
+        let rc = unsafe {
+            libc::send(self.fd, msg.as_ptr() as *const libc::c_void,
+                msg.len(), 0)
+        };
+        if rc < 0 {
+            return Err(format!("netlink send failed: {}",
+                io::Error::last_os_error()));
+        }
+        let mut buf = [0u8; 4096];
+        let n = unsafe {
+            libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(), 0)
+        };
+        if n < 0 {
+            return Err(format!("netlink recv failed: {}",
+                io::Error::last_os_error()));
+        }
+        if (n as usize) < size_of::<NlMsgHdr>() + size_of::<i32>() {
+            return Err(format!("netlink reply too short"));
+        }
+        let errno = unsafe {
+            *(buf[size_of::<NlMsgHdr>()..].as_ptr() as *const i32)
+        };
+        if errno != 0 {
+            return Err(format!("netlink request failed: {}",
+                io::Error::from_raw_os_error(-errno)));
+        }
+        Ok(())
+    }
+
+    /// Sends an `RTM_GETLINK` dump request and collects every link's
+    /// `(ifindex, name)` from the replies. There's no way to filter a
+    /// `GETLINK` dump by name kernel-side, so this always walks the full
+    /// link list; `get_link_index` and `link_exists` are the only callers
+    /// and link counts
+    /// per netns are tiny (a handful of veths/bridges at most).
+    fn dump_links(&self) -> Result<Vec<(i32, String)>, String> {
+        let mut msg = Message::new(RTM_GETLINK, NLM_F_DUMP);
+        msg.push(&IfInfoMsg {
+            ifi_family: 0, _pad: 0, ifi_type: 0, ifi_index: 0,
+            ifi_flags: 0, ifi_change: 0,
+        });
+        let bytes = msg.finish();
+        let rc = unsafe {
+            libc::send(self.fd, bytes.as_ptr() as *const libc::c_void,
+                bytes.len(), 0)
+        };
+        if rc < 0 {
+            return Err(format!("netlink send failed: {}",
+                io::Error::last_os_error()));
+        }
+
+        let mut links = Vec::new();
+        let mut buf = [0u8; 8192];
+        'recv: loop {
+            let n = unsafe {
+                libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(), 0)
+            };
+            if n < 0 {
+                return Err(format!("netlink recv failed: {}",
+                    io::Error::last_os_error()));
+            }
+            let n = n as usize;
+            let mut off = 0usize;
+            while off + size_of::<NlMsgHdr>() <= n {
+                let hdr = unsafe {
+                    &*(buf[off..].as_ptr() as *const NlMsgHdr)
+                };
+                let msg_len = hdr.nlmsg_len as usize;
+                if msg_len < size_of::<NlMsgHdr>() || off + msg_len > n {
+                    break;
+                }
+                if hdr.nlmsg_type == NLMSG_DONE {
+                    break 'recv;
+                } else if hdr.nlmsg_type == NLMSG_ERROR {
+                    let errno = unsafe {
+                        *(buf[off + size_of::<NlMsgHdr>()..].as_ptr()
+                            as *const i32)
+                    };
+                    if errno != 0 {
+                        return Err(format!("netlink dump failed: {}",
+                            io::Error::from_raw_os_error(-errno)));
+                    }
+                } else if hdr.nlmsg_type == RTM_NEWLINK {
+                    let ifi = unsafe {
+                        &*(buf[off + size_of::<NlMsgHdr>()..].as_ptr()
+                            as *const IfInfoMsg)
+                    };
+                    let attr_start = off + size_of::<NlMsgHdr>()
+                        + size_of::<IfInfoMsg>();
+                    let attr_end = off + msg_len;
+                    for (atype, data) in
+                        parse_attrs(&buf[attr_start..attr_end])
+                    {
+                        if atype == IFLA_IFNAME && data.last() == Some(&0) {
+                            let name = String::from_utf8_lossy(
+                                &data[..data.len() - 1]).into_owned();
+                            links.push((ifi.ifi_index, name));
+                        }
+                    }
+                }
+                off += (msg_len + 3) & !3;
+            }
+        }
+        Ok(links)
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+/// Looks up the kernel-assigned ifindex of a link we just created (or
+/// that a namespace we just entered already has), by name.
+fn get_link_index(name: &str) -> Result<i32, String> {
+    let links = NetlinkSocket::open()?.dump_links()?;
+    links.into_iter().find(|&(_, ref n)| n == name)
+        .map(|(idx, _)| idx)
+        .ok_or_else(|| format!("No such network link: {:?}", name))
+}
+
+fn link_exists(name: &str) -> Result<bool, String> {
+    let links = NetlinkSocket::open()?.dump_links()?;
+    Ok(links.iter().any(|&(_, ref n)| n == name))
+}
+
+fn parse_ipv4(s: &str) -> Result<[u8; 4], String> {
+    let mut out = [0u8; 4];
+    let parts: Vec<&str> = s.split('.').collect();
+    if parts.len() != 4 {
+        return Err(format!("Invalid IPv4 address: {:?}", s));
+    }
+    for (i, part) in parts.iter().enumerate() {
+        out[i] = try!(part.parse::<u8>()
+            .map_err(|_| format!("Invalid IPv4 address: {:?}", s)));
+    }
+    Ok(out)
+}
+
+fn new_link(kind: &str, ifname: &str,
+    peer: Option<&str>, netns_fd: Option<RawFd>)
+    -> Result<(), String>
+{
+    let mut msg = Message::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL |
+        NLM_F_ACK);
+    msg.push(&IfInfoMsg {
+        ifi_family: 0,
+        _pad: 0,
+        ifi_type: 0,
+        ifi_index: 0,
+        ifi_flags: 0,
+        ifi_change: 0,
+    });
+    msg.attr_str(IFLA_IFNAME, ifname);
+    if let Some(fd) = netns_fd {
+        msg.attr_u32(IFLA_NET_NS_FD, fd as u32);
+    }
+    let info_pos = msg.nest_start(IFLA_LINKINFO);
+    msg.attr_str(IFLA_INFO_KIND, kind);
+    if let Some(peer_name) = peer {
+        let data_pos = msg.nest_start(IFLA_INFO_DATA);
+        let peer_pos = msg.nest_start(IFLA_VETH_INFO_PEER);
+        msg.push(&IfInfoMsg {
+            ifi_family: 0, _pad: 0, ifi_type: 0, ifi_index: 0,
+            ifi_flags: 0, ifi_change: 0,
+        });
+        msg.attr_str(IFLA_IFNAME, peer_name);
+        msg.nest_end(peer_pos);
+        msg.nest_end(data_pos);
+    }
+    msg.nest_end(info_pos);
+    NetlinkSocket::open()?.talk(msg.finish())
+        .map_err(|e| format!("Can't create {} {:?}: {}", kind, ifname, e))
+}
+
+/// Creates a bridge device. Replaces `ip link add <name> type bridge`.
+fn create_bridge(name: &str) -> Result<(), String> {
+    new_link("bridge", name, None, None)
+}
+
+/// Creates a veth pair. Replaces
+/// `ip link add <name> type veth peer name <peer>`.
+fn create_veth(name: &str, peer: &str) -> Result<(), String> {
+    new_link("veth", name, Some(peer), None)
+}
+
+/// Moves an already-created link into another network namespace by file
+/// descriptor.  Replaces `ip link set <name> netns <pid>`.
+fn move_to_netns(ifindex: i32, netns_fd: RawFd) -> Result<(), String> {
+    let mut msg = Message::new(RTM_NEWLINK, NLM_F_ACK);
+    msg.push(&IfInfoMsg {
+        ifi_family: 0,
+        _pad: 0,
+        ifi_type: 0,
+        ifi_index: ifindex,
+        ifi_flags: 0,
+        ifi_change: 0,
+    });
+    msg.attr_u32(IFLA_NET_NS_FD, netns_fd as u32);
+    NetlinkSocket::open()?.talk(msg.finish())
+        .map_err(|e| format!("Can't move link to netns: {}", e))
+}
+
+/// Brings a link up. Replaces `ip link set <name> up`.
+fn set_link_up(ifindex: i32) -> Result<(), String> {
+    let mut msg = Message::new(RTM_NEWLINK, NLM_F_ACK);
+    msg.push(&IfInfoMsg {
+        ifi_family: 0,
+        _pad: 0,
+        ifi_type: 0,
+        ifi_index: ifindex,
+        ifi_flags: IFF_UP,
+        ifi_change: IFF_UP,
+    });
+    NetlinkSocket::open()?.talk(msg.finish())
+        .map_err(|e| format!("Can't bring link up: {}", e))
+}
+
+/// Enslaves a link to a bridge (or other master device) by ifindex.
+/// Replaces `ip link set <name> master <bridge>`.
+fn set_master(ifindex: i32, master_idx: i32) -> Result<(), String> {
+    let mut msg = Message::new(RTM_NEWLINK, NLM_F_ACK);
+    msg.push(&IfInfoMsg {
+        ifi_family: 0,
+        _pad: 0,
+        ifi_type: 0,
+        ifi_index: ifindex,
+        ifi_flags: 0,
+        ifi_change: 0,
+    });
+    msg.attr_u32(IFLA_MASTER, master_idx as u32);
+    NetlinkSocket::open()?.talk(msg.finish())
+        .map_err(|e| format!("Can't set master: {}", e))
+}
+
+/// Assigns an address to a link. Replaces `ip addr add <cidr> dev <name>`.
+fn add_addr(ifindex: i32, addr: [u8; 4], prefixlen: u8)
+    -> Result<(), String>
+{
+    let mut msg = Message::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_ACK);
+    msg.push(&IfAddrMsg {
+        ifa_family: AF_INET,
+        ifa_prefixlen: prefixlen,
+        ifa_flags: 0,
+        ifa_scope: 0,
+        ifa_index: ifindex,
+    });
+    msg.attr(IFA_LOCAL, &addr);
+    msg.attr(IFA_ADDRESS, &addr);
+    NetlinkSocket::open()?.talk(msg.finish())
+        .map_err(|e| format!("Can't add address: {}", e))
+}
+
+/// Installs a route. Replaces
+/// `ip route add <dest> via <gw> dev <name>`.
+fn add_route(dest: [u8; 4], dst_len: u8, gateway: [u8; 4], oif: i32)
+    -> Result<(), String>
+{
+    let mut msg = Message::new(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_ACK);
+    msg.push(&RtMsg {
+        rtm_family: AF_INET,
+        rtm_dst_len: dst_len,
+        rtm_src_len: 0,
+        rtm_tos: 0,
+        rtm_table: RT_TABLE_MAIN,
+        rtm_protocol: RTPROT_STATIC,
+        rtm_scope: RT_SCOPE_UNIVERSE,
+        rtm_type: RTN_UNICAST,
+        rtm_flags: 0,
+    });
+    msg.attr(RTA_DST, &dest);
+    msg.attr(RTA_GATEWAY, &gateway);
+    msg.attr_u32(RTA_OIF, oif as u32);
+    NetlinkSocket::open()?.talk(msg.finish())
+        .map_err(|e| format!("Can't add route: {}", e))
+}
+
+pub fn is_netns_set_up() -> bool {
+    namespace_dir().join("netns").exists()
+}
+
+pub fn namespace_dir() -> PathBuf {
+    PathBuf::from("/run/vagga/network")
+}
+
+/// Enters the persistent network namespace `vagga _create_netns` pinned at
+/// `namespace_dir().join("netns")`, so bridges/veths this process goes on
+/// to create land in the shared gateway namespace rather than whatever
+/// namespace the caller happened to start in. The caller is expected to
+/// `unshare_namespace(NewMount)` its own fresh mount namespace separately
+/// (supervisor.rs does, right after calling this) for its own tmpfs/bind
+/// mounts -- this function only ever touches networking.
+pub fn join_gateway_namespaces() -> Result<(), String> {
+    let path = namespace_dir().join("netns");
+    let file = try!(File::open(&path)
+        .map_err(|e| format!("Can't open gateway netns {:?}: {}", path, e)));
+    let rc = unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) };
+    if rc != 0 {
+        return Err(format!("Can't join gateway netns {:?}: {}", path,
+            io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Returns the first three octets, `172.<18+index>.0`, of the /24 subnet
+/// reserved for the `index`'th virtual network, so that several networks
+/// created by the same supervise command never overlap.
+pub fn subnet_for(index: usize) -> String {
+    format!("172.{}.0", 18 + index)
+}
+
+/// Returns the bridge device name `setup_bridge` creates for the
+/// `index`'th virtual network. Callers that need to look that bridge
+/// back up (e.g. to enslave a container's veth to it) must use this
+/// instead of recomputing the name themselves.
+pub fn bridge_name_for(index: usize) -> String {
+    format!("vagga{}", index)
+}
+
+/// Unshares a new namespace of `clone_flag`'s kind, bind-mounting
+/// `/proc/self/ns/<proc_ns>` onto `path` so it can be re-entered by path
+/// later even after every process that was ever inside it has exited.
+/// If `path` already exists it's entered in place of creating a new one,
+/// so repeated calls (e.g. on a respawn) are safe.
+fn create_and_enter_ns(path: &Path, clone_flag: libc::c_int, proc_ns: &str)
+    -> Result<(), String>
+{
+    if path.exists() {
+        let file = try!(File::open(path)
+            .map_err(|e| format!("Can't open {:?}: {}", path, e)));
+        let rc = unsafe { libc::setns(file.as_raw_fd(), clone_flag) };
+        if rc != 0 {
+            return Err(format!("Can't enter namespace {:?}: {}", path,
+                io::Error::last_os_error()));
+        }
+        return Ok(());
+    }
+    try!(File::create(path)
+        .map_err(|e| format!("Can't create {:?}: {}", path, e)));
+    if unsafe { libc::unshare(clone_flag) } != 0 {
+        let err = format!("Can't create namespace: {}",
+            io::Error::last_os_error());
+        let _ = ::std::fs::remove_file(path);
+        return Err(err);
+    }
+    let result = pin_current_ns(path, proc_ns);
+    if result.is_err() {
+        let _ = ::std::fs::remove_file(path);
+    }
+    result
+}
+
+/// Like `create_and_enter_ns`, but pins the new namespace at `path`
+/// without moving the calling process into it: a short-lived forked
+/// helper does the unshare and bind-mount instead, so e.g. a bridge's
+/// netlink operations in the caller's own namespace aren't disturbed.
+fn create_ns(path: &Path, clone_flag: libc::c_int, proc_ns: &str)
+    -> Result<(), String>
+{
+    if path.exists() {
+        return Ok(());
+    }
+    try!(File::create(path)
+        .map_err(|e| format!("Can't create {:?}: {}", path, e)));
+    match try!(fork().map_err(|e| format!("Can't fork: {}", e))) {
+        ForkResult::Child => {
+            let rc = unsafe { libc::unshare(clone_flag) };
+            if rc != 0 {
+                ::std::process::exit(1);
+            }
+            ::std::process::exit(match pin_current_ns(path, proc_ns) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            });
+        }
+        ForkResult::Parent { child } => {
+            match waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, 0)) => Ok(()),
+                _ => {
+                    // Don't leave the placeholder file behind: a later
+                    // retry's `path.exists()` check would otherwise
+                    // treat this namespace as already set up and skip
+                    // creating it for real.
+                    let _ = ::std::fs::remove_file(path);
+                    Err(format!("Can't create namespace at {:?}", path))
+                }
+            }
+        }
+    }
+}
+
+fn pin_current_ns(path: &Path, proc_ns: &str) -> Result<(), String> {
+    let src = CString::new(format!("/proc/self/ns/{}", proc_ns)).unwrap();
+    let dst = match path.to_str() {
+        Some(s) => CString::new(s).unwrap(),
+        None => return Err(format!("Non-utf8 namespace path: {:?}", path)),
+    };
+    let rc = unsafe {
+        libc::mount(src.as_ptr(), dst.as_ptr(), ptr::null(),
+            libc::MS_BIND, ptr::null())
+    };
+    if rc != 0 {
+        return Err(format!("Can't pin namespace at {:?}: {}", path,
+            io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn write_err(fd: RawFd, msg: &str) {
+    unsafe {
+        libc::write(fd, msg.as_ptr() as *const libc::c_void, msg.len());
+        libc::close(fd);
+    }
+}
+
+/// Runs `work` inside the namespace of `clone_flag`'s kind pinned at
+/// `ns_path`, in a short-lived forked helper, so the caller's own
+/// namespace is left untouched. On failure the helper's actual error
+/// (a `setns` errno or whatever `work` returned) is piped back to the
+/// parent instead of being lost to the helper's own stdout.
+fn configure_in_ns<F>(ns_path: &Path, clone_flag: libc::c_int, work: F)
+    -> Result<(), String>
+    where F: FnOnce() -> Result<(), String>
+{
+    let file = try!(File::open(ns_path)
+        .map_err(|e| format!("Can't open {:?}: {}", ns_path, e)));
+    let (read_fd, write_fd) = try!(pipe()
+        .map_err(|e| format!("Can't create pipe: {}", e)));
+    match try!(fork().map_err(|e| format!("Can't fork: {}", e))) {
+        ForkResult::Child => {
+            unsafe { libc::close(read_fd); }
+            let rc = unsafe {
+                libc::setns(file.as_raw_fd(), clone_flag)
+            };
+            if rc != 0 {
+                write_err(write_fd, &format!("Can't enter namespace: {}",
+                    io::Error::last_os_error()));
+                ::std::process::exit(1);
+            }
+            let code = match work() {
+                Ok(()) => 0,
+                Err(e) => {
+                    write_err(write_fd, &e);
+                    1
+                }
+            };
+            ::std::process::exit(code);
+        }
+        ForkResult::Parent { child } => {
+            unsafe { libc::close(write_fd); }
+            let mut err_msg = Vec::new();
+            let mut chunk = [0u8; 256];
+            loop {
+                let n = unsafe {
+                    libc::read(read_fd, chunk.as_mut_ptr() as *mut _,
+                        chunk.len())
+                };
+                if n <= 0 {
+                    break;
+                }
+                err_msg.extend_from_slice(&chunk[..n as usize]);
+            }
+            unsafe { libc::close(read_fd); }
+            match waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, 0)) => Ok(()),
+                _ => {
+                    let detail = String::from_utf8_lossy(&err_msg);
+                    if detail.is_empty() {
+                        Err(format!(
+                            "Failed to configure namespace {:?}", ns_path))
+                    } else {
+                        Err(format!(
+                            "Failed to configure namespace {:?}: {}",
+                            ns_path, detail))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Creates the bridge for a single virtual network, in its own network
+/// namespace pinned at `bridge_ns` so two networks' bridges can never
+/// reach each other, even if their subnets were ever misconfigured to
+/// overlap. Unlike the old implementation this never shells out to `ip`;
+/// every link and address is installed over rtnetlink, so errors returned
+/// here come from errno values and lookups, not scraped command output. `index`
+/// picks the network's subnet and keeps its bridge device name unique
+/// among the sibling networks of the same supervise command.
+pub fn setup_bridge(bridge_ns: &Path, index: usize)
+    -> Result<String, String>
+{
+    try!(create_and_enter_ns(bridge_ns, libc::CLONE_NEWNET, "net"));
+
+    let name = bridge_name_for(index);
+    create_bridge(&name)?;
+    let ifindex = get_link_index(&name)?;
+    set_link_up(ifindex)?;
+    let gateway = format!("{}.254", subnet_for(index));
+    add_addr(ifindex, try!(parse_ipv4(&gateway)), 24)?;
+    Ok(gateway)
+}
+
+/// Creates a veth pair for a single container, enslaves the host end to
+/// `bridge_name` and moves the container end into its own net/uts
+/// namespaces (pinned at `net_ns`/`uts_ns`): the container side gets
+/// `ip`/24 and a default route via the bridge's gateway, and `hostname`
+/// is set inside `uts_ns`. Must be called from within `bridge_name`'s own
+/// net namespace, as set up by `setup_bridge`.
+pub fn setup_container(net_ns: &Path, uts_ns: &Path,
+    name: &str, ip: &str, hostname: &str, bridge_name: &str)
+    -> Result<(), String>
+{
+    let host_side = format!("v_{}", name);
+    let peer_side = format!("vp_{}", name);
+
+    if try!(link_exists(&host_side)) {
+        // Already set up by an earlier spawn of this same child: the
+        // veth, its namespaces, address and hostname all persist across
+        // a crash/restart (nothing tears them down when the child
+        // dies), so a respawn has nothing left to configure.
+        return Ok(());
+    }
+
+    create_veth(&host_side, &peer_side)?;
+
+    let host_idx = get_link_index(&host_side)?;
+    set_link_up(host_idx)?;
+    let bridge_idx = get_link_index(bridge_name)?;
+    set_master(host_idx, bridge_idx)?;
+    let peer_idx = get_link_index(&peer_side)?;
+
+    try!(create_ns(net_ns, libc::CLONE_NEWNET, "net"));
+    try!(create_ns(uts_ns, libc::CLONE_NEWUTS, "uts"));
+
+    let netns_file = try!(File::open(net_ns)
+        .map_err(|e| format!("Can't open {:?}: {}", net_ns, e)));
+    move_to_netns(peer_idx, netns_file.as_raw_fd())?;
+
+    let addr = try!(parse_ipv4(ip));
+    let gateway = [addr[0], addr[1], addr[2], 254];
+    let peer_side_in_ns = peer_side.clone();
+    try!(configure_in_ns(net_ns, libc::CLONE_NEWNET, move || {
+        let idx = get_link_index(&peer_side_in_ns)?;
+        set_link_up(idx)?;
+        add_addr(idx, addr, 24)?;
+        add_route([0, 0, 0, 0], 0, gateway, idx)?;
+        Ok(())
+    }));
+
+    let hostname = hostname.to_string();
+    try!(configure_in_ns(uts_ns, libc::CLONE_NEWUTS, move || {
+        let c_hostname = try!(CString::new(hostname.clone())
+            .map_err(|e| format!("Bad hostname {:?}: {}", hostname, e)));
+        let rc = unsafe {
+            libc::sethostname(c_hostname.as_ptr() as *const _,
+                c_hostname.as_bytes().len())
+        };
+        if rc != 0 {
+            return Err(format!("Can't set hostname: {}",
+                io::Error::last_os_error()));
+        }
+        Ok(())
+    }));
+
+    Ok(())
+}
+
+/// Owns the DNAT rules that forward a network's external ports into its
+/// containers: `(ext_port, container_ip, int_port)` triples, installed
+/// against the gateway namespace pinned at `netns` (the same namespace
+/// `join_gateway_namespaces` enters). Dropping the guard removes exactly
+/// the rules it successfully added -- never the full `forwards` list --
+/// so a partial `start_forwarding` failure doesn't try to delete rules
+/// that were never installed.
+pub struct PortForwardGuard {
+    netns: PathBuf,
+    forwards: Vec<(u16, String, u16)>,
+    added: Vec<(u16, String, u16)>,
+}
+
+impl PortForwardGuard {
+    pub fn new(netns: &Path, forwards: Vec<(u16, String, u16)>)
+        -> PortForwardGuard
+    {
+        PortForwardGuard {
+            netns: netns.to_path_buf(),
+            forwards: forwards,
+            added: vec!(),
+        }
+    }
+    pub fn start_forwarding(&mut self) -> Result<(), String> {
+        for &(ext_port, ref ip, int_port) in self.forwards.iter() {
+            try!(run_iptables(&self.netns, &[
+                "-t", "nat", "-A", "PREROUTING",
+                "-p", "tcp", "--dport", &ext_port.to_string(),
+                "-j", "DNAT",
+                "--to-destination", &format!("{}:{}", ip, int_port),
+            ]));
+            self.added.push((ext_port, ip.clone(), int_port));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PortForwardGuard {
+    fn drop(&mut self) {
+        for &(ext_port, ref ip, int_port) in self.added.iter() {
+            // Best-effort: nothing left to report a removal failure to,
+            // and leaving one stale rule shouldn't stop the rest from
+            // coming down.
+            let _ = run_iptables(&self.netns, &[
+                "-t", "nat", "-D", "PREROUTING",
+                "-p", "tcp", "--dport", &ext_port.to_string(),
+                "-j", "DNAT",
+                "--to-destination", &format!("{}:{}", ip, int_port),
+            ]);
+        }
+    }
+}
+
+/// Runs `iptables` inside the network namespace pinned at `netns`, via
+/// `nsenter` rather than `setns`-ing this process itself: unlike the
+/// rtnetlink calls elsewhere in this file, there's no rule-management
+/// API nicer than shelling out to the `iptables` binary.
+fn run_iptables(netns: &Path, args: &[&str]) -> Result<(), String> {
+    let netns_arg = format!("--net={}", netns.display());
+    let status = try!(Command::new("nsenter")
+        .arg(&netns_arg)
+        .arg("--")
+        .arg("iptables")
+        .args(args)
+        .status()
+        .map_err(|e| format!("Can't run iptables: {}", e)));
+    if !status.success() {
+        return Err(format!("iptables {:?} failed: {}", args, status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Message, parse_attrs, parse_ipv4, subnet_for,
+        bridge_name_for, IFLA_IFNAME, RTM_NEWLINK, NLM_F_ACK};
+
+    #[test]
+    fn message_header_records_type_and_flags() {
+        let msg = Message::new(RTM_NEWLINK, NLM_F_ACK);
+        let bytes = msg.finish();
+        let len = u32::from_ne_bytes([bytes[0], bytes[1], bytes[2],
+            bytes[3]]);
+        assert_eq!(len as usize, bytes.len());
+        let msg_type = u16::from_ne_bytes([bytes[4], bytes[5]]);
+        assert_eq!(msg_type, RTM_NEWLINK);
+    }
+
+    #[test]
+    fn attr_round_trips_through_parse_attrs() {
+        let mut msg = Message::new(RTM_NEWLINK, NLM_F_ACK);
+        msg.attr_str(IFLA_IFNAME, "vagga0");
+        let bytes = msg.finish();
+        let attrs = parse_attrs(&bytes[16..]);
+        assert_eq!(attrs.len(), 1);
+        let (atype, data) = &attrs[0];
+        assert_eq!(*atype, IFLA_IFNAME);
+        assert_eq!(&data[..data.len() - 1], b"vagga0");
+    }
+
+    #[test]
+    fn parse_ipv4_accepts_dotted_quad() {
+        assert_eq!(parse_ipv4("172.18.0.254").unwrap(),
+            [172, 18, 0, 254]);
+    }
+
+    #[test]
+    fn parse_ipv4_rejects_garbage() {
+        assert!(parse_ipv4("not-an-ip").is_err());
+        assert!(parse_ipv4("1.2.3").is_err());
+    }
+
+    #[test]
+    fn subnet_for_is_stable_and_non_overlapping() {
+        assert_eq!(subnet_for(0), "172.18.0");
+        assert_eq!(subnet_for(1), "172.19.0");
+        assert_ne!(subnet_for(0), subnet_for(1));
+    }
+
+    #[test]
+    fn bridge_name_for_is_stable_and_unique() {
+        assert_eq!(bridge_name_for(0), "vagga0");
+        assert_ne!(bridge_name_for(0), bridge_name_for(1));
+    }
+}
@@ -1,15 +1,22 @@
 use std::cell::Cell;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashSet, VecDeque};
 use std::collections::HashMap;
 use std::env::current_exe;
-use std::io::{stdout, stderr};
-use std::path::Path;
+use std::fs::File;
+use std::io::{stdout, stderr, BufRead, BufReader, Write};
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process::Command as StdCommand;
 use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use argparse::{ArgumentParser};
+use argparse::{ArgumentParser, Collect};
 use signal::trap::Trap;
-use nix::sys::signal::{SIGINT, SIGTERM, SIGCHLD};
-use unshare::{Command, Namespace, reap_zombies};
+use nix::sys::signal::{SIGINT, SIGTERM, SIGCHLD, SIGUSR1, kill};
+use nix::unistd::{pipe, getpid};
+use unshare::{Command, Namespace, Child, reap_zombies};
 
 use container::mount::{mount_tmpfs};
 use container::nsutil::{set_namespace, unshare_namespace};
@@ -17,7 +24,8 @@ use container::container::Namespace::{NewNet, NewUts, NewMount};
 use container::uidmap::get_max_uidmap;
 use config::Config;
 use config::command::{SuperviseInfo, Networking};
-use config::command::SuperviseMode::{stop_on_failure};
+use config::command::SuperviseMode::{stop_on_failure, restart_on_failure,
+    restart_always};
 use config::command::ChildCommand::{BridgeCommand};
 
 use super::network;
@@ -27,20 +35,518 @@ use file_util::create_dir;
 use path_util::PathExt;
 use process_util::{set_uidmap, convert_status};
 
+/// A crash-looping child backs off exponentially between respawns, up to
+/// this cap, and is given up on (tripping the global shutdown path)
+/// after `MAX_RESTART_ATTEMPTS` respawns without a clean, long-lived run.
+const BASE_BACKOFF_MS: u64 = 500;
+const MAX_BACKOFF_MS: u64 = 30_000;
+const MAX_RESTART_ATTEMPTS: u32 = 8;
+/// A respawned child that stays up at least this many seconds resets its
+/// own backoff counter, so a flaky process doesn't permanently inherit
+/// the long delays earned by an earlier crash loop.
+const BACKOFF_RESET_AFTER_SECS: u64 = 60;
+
+/// Where and how to (re)spawn a single supervised child; captured once at
+/// startup so a restart can replay the exact same setup as the original
+/// spawn.
+#[derive(Clone)]
+enum ChildPlacement {
+    // `host_netns_fd` re-enters the supervisor's original network
+    // namespace before spawning, when the spawn loop also handles netns
+    // children: `depends_on` ordering can interleave the two, so which
+    // namespace the supervisor happens to be sitting in at spawn time
+    // can no longer be assumed from spawn order alone.
+    HostNet { host_netns_fd: Option<RawFd> },
+    NetNs {
+        bridge_ns: PathBuf,
+        nsdir: PathBuf,
+        net_name: String,
+        bridge_name: String,
+    },
+}
+
+/// Per-child restart bookkeeping for `restart_on_failure`/`restart_always`.
+struct Backoff {
+    attempts: u32,
+    started_at: Instant,
+}
+
+/// Exponential backoff delay (in ms) before the `attempts`-th restart,
+/// doubling each time up to `MAX_BACKOFF_MS`.
+fn backoff_delay_ms(attempts: u32) -> u64 {
+    ::std::cmp::min(BASE_BACKOFF_MS << attempts.min(6), MAX_BACKOFF_MS)
+}
+
+
+/// Everything needed to stand up one isolated virtual network: the
+/// containers placed on it, its port forwards (empty when `internal`)
+/// and the bridge-only commands (e.g. a DNS server) that run on it.
+struct NetworkGroup {
+    internal: bool,
+    members: Vec<String>,
+    bridges: Vec<String>,
+    forwards: Vec<(u16, String, u16)>,
+}
+
+impl NetworkGroup {
+    fn new() -> NetworkGroup {
+        NetworkGroup {
+            internal: false,
+            members: vec!(),
+            bridges: vec!(),
+            forwards: vec!(),
+        }
+    }
+}
+
+/// Full-graph cycle/unknown-dependency check over every name in `deps`,
+/// independent of any one run's `--only`/`--exclude` selection. Pulled out
+/// to take a plain name -> depends_on map rather than `SuperviseInfo`
+/// directly so it (and `toposort`) are unit-testable without the `config`
+/// crate's types.
+fn detect_cycle(deps: &HashMap<String, Vec<String>>) -> Result<(), String> {
+    let mut done = HashSet::new();
+    let mut visiting = HashSet::new();
+
+    fn visit(name: &String, deps: &HashMap<String, Vec<String>>,
+        done: &mut HashSet<String>, visiting: &mut HashSet<String>)
+        -> Result<(), String>
+    {
+        if done.contains(name) {
+            return Ok(());
+        }
+        if !visiting.insert(name.clone()) {
+            return Err(format!(
+                "Dependency cycle in `depends_on` involving {:?}", name));
+        }
+        if let Some(children) = deps.get(name) {
+            for dep in children.iter() {
+                if !deps.contains_key(dep) {
+                    return Err(format!(
+                        "{:?} depends on unknown process {:?}", name, dep));
+                }
+                try!(visit(dep, deps, done, visiting));
+            }
+        }
+        visiting.remove(name);
+        done.insert(name.clone());
+        Ok(())
+    }
+
+    for name in deps.keys() {
+        try!(visit(name, deps, &mut done, &mut visiting));
+    }
+    Ok(())
+}
+
+/// Orders `names` so that every child comes after everything listed in
+/// its `depends_on`, returning an error if a cycle is found. A dependency
+/// known in `deps` but absent from `names` (excluded from this particular
+/// run via `--only`/`--exclude`) is skipped rather than ordered, since
+/// nothing is spawning it this run; `detect_cycle` is what catches a cycle
+/// involving such an excluded child instead.
+fn toposort(deps: &HashMap<String, Vec<String>>, names: &Vec<String>)
+    -> Result<Vec<String>, String>
+{
+    let wanted: HashSet<&String> = names.iter().collect();
+    let mut order = Vec::with_capacity(names.len());
+    let mut done = HashSet::new();
+    let mut visiting = HashSet::new();
+
+    fn visit(name: &String, deps: &HashMap<String, Vec<String>>,
+        wanted: &HashSet<&String>, done: &mut HashSet<String>,
+        visiting: &mut HashSet<String>, order: &mut Vec<String>)
+        -> Result<(), String>
+    {
+        if done.contains(name) {
+            return Ok(());
+        }
+        if !visiting.insert(name.clone()) {
+            return Err(format!(
+                "Dependency cycle in `depends_on` involving {:?}", name));
+        }
+        if let Some(children) = deps.get(name) {
+            for dep in children.iter() {
+                if !wanted.contains(dep) {
+                    if deps.contains_key(dep) {
+                        continue;
+                    }
+                    return Err(format!(
+                        "{:?} depends on unknown process {:?}",
+                        name, dep));
+                }
+                try!(visit(dep, deps, wanted, done, visiting, order));
+            }
+        }
+        visiting.remove(name);
+        done.insert(name.clone());
+        order.push(name.clone());
+        Ok(())
+    }
+
+    for name in names.iter() {
+        try!(visit(name, deps, &wanted, &mut done, &mut visiting,
+            &mut order));
+    }
+    Ok(order)
+}
+
+/// Every configured child's `depends_on`, as a plain map `toposort` and
+/// `detect_cycle` can work with directly.
+fn dependency_map(sup: &SuperviseInfo) -> HashMap<String, Vec<String>> {
+    sup.children.iter()
+        .map(|(name, child)| (name.clone(), child.depends_on()))
+        .collect()
+}
+
+/// Validates `depends_on` across *every* configured child, regardless of
+/// what `--only`/`--exclude` will later select for a given run -- a cycle
+/// (or a dangling reference) involving an excluded child, or in a
+/// supervise block nobody has invoked yet, is still a config error.
+///
+/// Ideally this runs once at config load time; that code isn't part of
+/// this source tree (confirmed: no `config` module source, no `main.rs`,
+/// no `Cargo.toml` anywhere here), so this is called as the first thing
+/// `run_supervise_command` does instead, which is the earliest point in
+/// this tree with access to the unfiltered `sup.children` set.
+fn validate_dependencies(sup: &SuperviseInfo) -> Result<(), String> {
+    detect_cycle(&dependency_map(sup))
+}
+
+fn toposort_children(sup: &SuperviseInfo, names: &Vec<String>)
+    -> Result<Vec<String>, String>
+{
+    toposort(&dependency_map(sup), names)
+}
+
+/// Blocks until the child named `dep` either writes a readiness byte to
+/// its notify pipe and closes it, or `timeout` elapses. A pipe closed
+/// without a byte written (e.g. the child exited first) is an error.
+fn wait_for_readiness(dep: &str, read_fd: RawFd, timeout: Duration)
+    -> Result<(), String>
+{
+    use nix::poll::{poll, PollFd, POLLIN};
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.checked_duration_since(Instant::now())
+            .unwrap_or(Duration::new(0, 0));
+        if remaining == Duration::new(0, 0) {
+            return Err(format!(
+                "Dependency {:?} did not become ready within {:?}",
+                dep, timeout));
+        }
+        let ms = (remaining.as_secs() * 1000) as i32 +
+            (remaining.subsec_nanos() / 1_000_000) as i32;
+        let mut fds = [PollFd::new(read_fd, POLLIN)];
+        match poll(&mut fds, ms) {
+            Ok(0) => continue,
+            Ok(_) => {
+                let mut buf = [0u8; 1];
+                let n = unsafe {
+                    ::libc::read(read_fd, buf.as_mut_ptr() as *mut _, 1)
+                };
+                if n == 1 {
+                    return Ok(());
+                }
+                return Err(format!(
+                    "Dependency {:?} closed its notify pipe without \
+                    signalling readiness", dep));
+            }
+            Err(e) => return Err(format!(
+                "Error polling readiness pipe for {:?}: {}", dep, e)),
+        }
+    }
+}
+
+/// Polls `wait_for` command until it exits successfully or `timeout`
+/// elapses; used for dependencies that can't be patched to notify us
+/// directly.
+fn wait_for_command(dep: &str, argv: &Vec<String>, timeout: Duration)
+    -> Result<(), String>
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        if argv.len() > 0 {
+            let status = StdCommand::new(&argv[0]).args(&argv[1..]).status();
+            if let Ok(s) = status {
+                if s.success() {
+                    return Ok(());
+                }
+            }
+        }
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "Dependency {:?} did not become ready within {:?}",
+                dep, timeout));
+        }
+        ::std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Spawns (or respawns) a single supervised child, reproducing whatever
+/// host-net or netns setup the original spawn used. Shared by the initial
+/// launch and by the restart path in `run_supervise_command`'s signal loop.
+fn spawn_child(cmdname: &str, workdir: &Path, name: &str,
+    sup: &SuperviseInfo, placement: &ChildPlacement, has_dependent: bool)
+    -> Result<Child, String>
+{
+    let child_cfg = sup.children.get(name).unwrap();
+    let mut cmd = Command::new("/proc/self/exe");
+    cmd.arg0("vagga_wrapper");
+    cmd.keep_sigmask();
+    cmd.arg(cmdname);
+    cmd.arg(name);
+    common_child_command_env(&mut cmd, Some(workdir));
+    // `vagga_wrapper` becomes pid 1 of the namespace created by this
+    // unshare. To avoid leaking descendants of the supervised command as
+    // zombies, that pid-1 process needs to run the real command through
+    // `wrapper::run_wrapped_command` (which double-forks and reaps via
+    // `wrapper::pid1`) rather than exec'ing it directly -- but neither
+    // `vagga_wrapper`'s `main()` nor anything else in this source tree
+    // actually calls `run_wrapped_command`, so that reaping does not
+    // happen yet; this `unshare` call alone does not fix the zombie
+    // problem it's guarding against.
+    cmd.unshare(
+        [Namespace::Mount, Namespace::Ipc, Namespace::Pid].iter().cloned());
+
+    match placement {
+        &ChildPlacement::HostNet { host_netns_fd } => {
+            if let Some(fd) = host_netns_fd {
+                // The supervisor may currently be sitting in a bridge's
+                // netns (left there by an earlier NetNs spawn in
+                // `depends_on` order), so explicitly return to the real
+                // host netns rather than assuming we're already in it.
+                let rc = unsafe { ::libc::setns(fd, ::libc::CLONE_NEWNET) };
+                if rc != 0 {
+                    return Err(format!("Error returning to host netns: {}",
+                        ::std::io::Error::last_os_error()));
+                }
+            }
+            set_uidmap(&mut cmd, &get_max_uidmap().unwrap(), true);
+        }
+        &ChildPlacement::NetNs { ref bridge_ns, ref nsdir, ref net_name,
+            ref bridge_name } =>
+        {
+            try!(set_namespace(bridge_ns, NewNet)
+                .map_err(|e| format!("Error setting netns: {}", e)));
+            if let &BridgeCommand(_) = child_cfg {
+                // Already setup by set_namespace
+                // But also need to mount namespace_dir into container
+                cmd.env("VAGGA_NAMESPACE_DIR", nsdir);
+            } else {
+                let netw = child_cfg.network().unwrap();
+                let net_ns = nsdir.join(format!("net.{}.{}", net_name,
+                    netw.ip));
+                let uts_ns = nsdir.join(format!("uts.{}.{}", net_name,
+                    netw.ip));
+                // TODO(tailhook) support multiple commands with same IP
+                try!(network::setup_container(&net_ns, &uts_ns,
+                    name, &netw.ip,
+                    netw.hostname.as_ref().unwrap_or(&name.to_string()),
+                    bridge_name));
+                try!(set_namespace(&net_ns, NewNet)
+                    .map_err(|e| format!("Error setting netns: {}", e)));
+                try!(set_namespace(&uts_ns, NewUts)
+                    .map_err(|e| format!("Error setting netns: {}", e)));
+            }
+        }
+    }
+
+    // Readiness gating only matters to whoever `depends_on` this child --
+    // it must not be mandatory plumbing for every spawn, or (a) any two
+    // children with no relationship to each other always serialize
+    // behind each other's ready_timeout, and (b) a pre-existing config
+    // entry that never writes to VAGGA_NOTIFY_FD (because nothing was
+    // ever going to wait on it) starts failing once ready_timeout
+    // elapses. `has_dependent` is precomputed by the caller from this
+    // run's actually-selected children, not the full config, so a
+    // dependent excluded via `--only`/`--exclude` doesn't force a wait
+    // that will never be satisfied either.
+    let notify_fds = if has_dependent && child_cfg.wait_for().is_none() {
+        let (read_fd, write_fd) = try!(pipe()
+            .map_err(|e| format!("Can't create notify pipe: {}", e)));
+        cmd.env("VAGGA_NOTIFY_FD", write_fd.to_string());
+        Some((read_fd, write_fd))
+    } else {
+        None
+    };
+
+    let child = try!(cmd.spawn().map_err(|e| format!("{}", e)));
+
+    if let Some((read_fd, write_fd)) = notify_fds {
+        unsafe { ::libc::close(write_fd); }
+        let result = wait_for_readiness(name, read_fd, child_cfg.ready_timeout());
+        unsafe { ::libc::close(read_fd); }
+        try!(result);
+    } else if has_dependent {
+        if let Some(argv) = child_cfg.wait_for() {
+            try!(wait_for_command(name, argv, child_cfg.ready_timeout()));
+        }
+    }
+
+    if let &ChildPlacement::NetNs { ref bridge_ns, .. } = placement {
+        // Leave the supervisor itself back in the bridge namespace, both
+        // to keep it alive and so the next spawn_child call (whether the
+        // next sibling or a later restart) starts from a known state.
+        try!(set_namespace(bridge_ns, NewNet)
+            .map_err(|e| format!("Error setting netns: {}", e)));
+    }
+
+    Ok(child)
+}
+
+/// A request queued by a control-socket connection, drained by the
+/// supervisor's signal loop on `SIGUSR1`. Each carries back a one-shot
+/// sender so the connection thread's response reflects what the signal
+/// loop actually did instead of just "it got enqueued".
+enum CtlCommand {
+    Stop(String, mpsc::Sender<Result<(), String>>),
+    Start(String, mpsc::Sender<Result<(), String>>),
+    Restart(String, mpsc::Sender<Result<(), String>>),
+}
+
+/// State shared between the supervisor's signal loop and the control
+/// socket's connection-handling threads: commands flow one way (`queue`),
+/// the latest known status the other (`status`), each behind its own lock
+/// so a `status` query never waits on the main loop draining `queue`.
+struct ControlState {
+    queue: Mutex<VecDeque<CtlCommand>>,
+    status: Mutex<Vec<(String, i32, String)>>, // (name, pid, network)
+}
+
+impl ControlState {
+    fn new() -> ControlState {
+        ControlState {
+            queue: Mutex::new(VecDeque::new()),
+            status: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+/// Splits one control-socket line into its verb and (trimmed) argument,
+/// e.g. `"stop foo"` -> `("stop", "foo")`, `"status"` -> `("status", "")`.
+fn parse_ctl_line(line: &str) -> (String, String) {
+    let line = line.trim();
+    let mut parts = line.splitn(2, ' ');
+    let verb = parts.next().unwrap_or("").to_string();
+    let arg = parts.next().unwrap_or("").trim().to_string();
+    (verb, arg)
+}
+
+/// Accepts connections on the vagga-internal control socket for as long
+/// as the supervisor runs. Each connection gets one line in, analogous to
+/// crosvm's `vm_control` socket: `stop <name>`, `start <name>`,
+/// `restart <name>` or `status`.
+fn run_control_socket(path: PathBuf, state: Arc<ControlState>) {
+    let _ = ::std::fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(l) => l,
+        Err(e) => {
+            println!("Can't bind control socket {:?}: {}", path, e);
+            return;
+        }
+    };
+    for conn in listener.incoming() {
+        let conn = match conn { Ok(c) => c, Err(_) => continue };
+        let state = state.clone();
+        ::std::thread::spawn(move || {
+            let mut reader = BufReader::new(match conn.try_clone() {
+                Ok(c) => c,
+                Err(_) => return,
+            });
+            let mut writer = conn;
+            let mut line = String::new();
+            if reader.read_line(&mut line).is_err() {
+                return;
+            }
+            let (verb, arg) = parse_ctl_line(&line);
+            match verb.as_str() {
+                "stop" | "start" | "restart" => {
+                    let (tx, rx) = mpsc::channel();
+                    let cmd = match verb.as_str() {
+                        "stop" => CtlCommand::Stop(arg, tx),
+                        "start" => CtlCommand::Start(arg, tx),
+                        _ => CtlCommand::Restart(arg, tx),
+                    };
+                    state.queue.lock().unwrap().push_back(cmd);
+                    try_raise_self();
+                    // The signal loop is what actually stops/starts/
+                    // restarts the process; wait for its real outcome
+                    // instead of claiming success the moment the command
+                    // is merely enqueued. A timeout guards against the
+                    // loop having already exited without draining it.
+                    match rx.recv_timeout(Duration::from_secs(10)) {
+                        Ok(Ok(())) => { writeln!(writer, "ok").ok(); }
+                        Ok(Err(e)) => {
+                            writeln!(writer, "error: {}", e).ok();
+                        }
+                        Err(_) => {
+                            // The signal loop may just be slow rather than
+                            // gone -- it still holds this command and may
+                            // act on it after we give up waiting, so don't
+                            // claim nothing happened.
+                            writeln!(writer, "error: timed out waiting \
+                                for supervisor (command may still be \
+                                applied)").ok();
+                        }
+                    }
+                }
+                "status" => {
+                    for &(ref name, pid, ref netw) in
+                        state.status.lock().unwrap().iter()
+                    {
+                        writeln!(writer, "{} {} {}", name, pid, netw).ok();
+                    }
+                }
+                _ => { writeln!(writer, "error: unknown command").ok(); }
+            }
+        });
+    }
+}
+
+/// `raise(2)` only guarantees delivery to the calling thread; this runs on
+/// a connection-handling thread, but the signal needs to land on the main
+/// thread's `Trap`, so send it to the whole process instead.
+fn try_raise_self() {
+    kill(getpid(), SIGUSR1).ok();
+}
+
+/// Rebuilds the snapshot the control socket's `status` command serves.
+fn snapshot_status(children: &HashMap<i32, (String, Child)>,
+    placements: &HashMap<String, ChildPlacement>)
+    -> Vec<(String, i32, String)>
+{
+    children.iter().map(|(&pid, &(ref name, _))| {
+        let netw = match placements.get(name) {
+            Some(&ChildPlacement::NetNs { ref net_name, .. }) => {
+                net_name.clone()
+            }
+            _ => "host".to_string(),
+        };
+        (name.clone(), pid, netw)
+    }).collect()
+}
 
 pub fn run_supervise_command(config: &Config, workdir: &Path,
     sup: &SuperviseInfo, cmdname: String, mut args: Vec<String>)
     -> Result<i32, String>
 {
-    if sup.mode != stop_on_failure {
-        panic!("Only stop-on-failure mode implemented");
-    }
+    // Over the full config, not whatever --only/--exclude narrows this
+    // run down to -- see `validate_dependencies`.
+    try!(validate_dependencies(sup));
+
+    let mut only = Vec::<String>::new();
+    let mut exclude = Vec::<String>::new();
     {
         args.insert(0, "vagga ".to_string() + &cmdname);
         let mut ap = ArgumentParser::new();
         ap.set_description(sup.description.as_ref().map(|x| &x[..])
             .unwrap_or("Run multiple processes simultaneously"));
-        // TODO(tailhook) implement --only and --exclude
+        ap.refer(&mut only).add_option(&["--only"], Collect,
+            "Only start this process (can be repeated)");
+        ap.refer(&mut exclude).add_option(&["--exclude"], Collect,
+            "Don't start this process (can be repeated)");
         match ap.parse(args, &mut stdout(), &mut stderr()) {
             Ok(()) => {}
             Err(0) => return Ok(0),
@@ -49,72 +555,102 @@ pub fn run_supervise_command(config: &Config, workdir: &Path,
             }
         }
     }
+    let is_selected = |name: &str| {
+        (only.is_empty() || only.iter().any(|x| x == name)) &&
+        !exclude.iter().any(|x| x == name)
+    };
 
     let mut containers = BTreeSet::new();
-    let mut containers_in_netns = vec!();
-    let mut bridges = vec!();
-    let mut containers_host_net = vec!();
-    let mut forwards = vec!();
-    let mut ports = vec!();
+    let mut all_names = vec!();
+    let mut host_net_names: HashSet<String> = HashSet::new();
+    let mut net_of_child: HashMap<String, String> = HashMap::new();
+    // One group per named virtual network (default network name is
+    // "default"); containers in different groups never share a bridge
+    // and so can't reach each other, mirroring Docker's network scoping.
+    let mut networks: HashMap<String, NetworkGroup> = HashMap::new();
     for (name, child) in sup.children.iter() {
+        if !is_selected(name) {
+            continue;
+        }
         let cont = child.get_container();
         if !containers.contains(cont) {
             containers.insert(cont.to_string());
             try!(build_container(config, cont));
         }
+        all_names.push(name.to_string());
         if let &BridgeCommand(_) = child {
-            bridges.push(name.to_string());
-        } else {
-            if let Some(ref netw) = child.network() {
-                containers_in_netns.push(name.to_string());
+            let net_name = child.network_name()
+                .unwrap_or("default".to_string());
+            networks.entry(net_name.clone()).or_insert_with(NetworkGroup::new)
+                .bridges.push(name.to_string());
+            net_of_child.insert(name.to_string(), net_name);
+        } else if let Some(ref netw) = child.network() {
+            let group = networks.entry(netw.name.clone())
+                .or_insert_with(NetworkGroup::new);
+            group.internal = netw.internal;
+            group.members.push(name.to_string());
+            if !netw.internal {
                 for (ext_port, int_port) in netw.ports.iter() {
-                     forwards.push((*ext_port, netw.ip.clone(), *int_port));
-                    ports.push(*ext_port);
+                    group.forwards.push((*ext_port, netw.ip.clone(),
+                        *int_port));
                 }
-            } else {
-                containers_host_net.push(name.to_string());
             }
+            net_of_child.insert(name.to_string(), netw.name.clone());
+        } else {
+            host_net_names.insert(name.to_string());
         }
     }
-    containers_in_netns.extend(bridges.into_iter()); // Bridges are just last
-    if containers_in_netns.len() > 0 && !network::is_netns_set_up() {
+    let containers_in_netns_count = networks.values()
+        .map(|g| g.members.len() + g.bridges.len()).fold(0, |a, b| a + b);
+    if containers_in_netns_count > 0 && !network::is_netns_set_up() {
         return Err(format!("Network namespace is not set up. You need to run \
             vagga _create_netns first"));
     }
-    debug!("Containers {} with host neworking, {} in netns",
-        containers_host_net.len(), containers_in_netns.len());
+    debug!("Containers {} with host neworking, {} in {} networks",
+        host_net_names.len(), containers_in_netns_count, networks.len());
 
-    let mut trap = Trap::trap(&[SIGINT, SIGTERM, SIGCHLD]);
-    let mut children = HashMap::new();
-    let mut error = false;
-    for name in containers_host_net.iter() {
-        let mut cmd = Command::new("/proc/self/exe");
-        cmd.arg0("vagga_wrapper");
-        cmd.keep_sigmask();
-        cmd.arg(&cmdname);
-        cmd.arg(&name);
-        common_child_command_env(&mut cmd, Some(workdir));
-        cmd.unshare(
-            [Namespace::Mount, Namespace::Ipc, Namespace::Pid].iter().cloned());
-        set_uidmap(&mut cmd, &get_max_uidmap().unwrap(), true);
-        match cmd.spawn() {
-            Ok(child) => { children.insert(child.pid(), (name, child)); }
-            Err(e) => {
-                if !error {
-                    println!(
-                        "---------- \
-                        Process {} could not be run: {}. Shutting down \
-                        -----------",
-                        name, e);
-                    error = true;
-                }
-            }
-        }
-    }
-    let mut port_forward_guard;
-    if containers_in_netns.len() > 0 {
-        let gwdir = network::namespace_dir();
-        let nsdir = gwdir.join("children");
+    // A single dependency graph across every selected child, regardless
+    // of which network (or host networking) it ends up placed on: a
+    // `depends_on` that crosses network boundaries (e.g. a resolver on
+    // one network depending on a DNS server on another) must still be
+    // enforced, which a toposort scoped to one spawn group can't do.
+    let global_order = try!(toposort_children(sup, &all_names));
+
+    // Readiness gating (notify pipe / wait_for) only matters to whoever
+    // actually `depends_on` a child *in this run* -- computed from the
+    // selected set (`all_names`), not the full config, so a dependent
+    // excluded via `--only`/`--exclude` doesn't force its (selected)
+    // dependency to wait on a signal that will never come.
+    let depended_on: HashSet<String> = all_names.iter()
+        .filter_map(|n| sup.children.get(n))
+        .flat_map(|c| c.depends_on().into_iter())
+        .collect();
+
+    // Sort for a deterministic subnet assignment across runs.
+    let mut net_names: Vec<&String> = networks.keys().collect();
+    net_names.sort();
+    let net_index: HashMap<String, usize> = net_names.iter().enumerate()
+        .map(|(i, n)| (n.to_string(), i)).collect();
+
+    // Captured before any set_namespace/unshare call can move the
+    // supervisor out of its original netns, so HostNet spawns can always
+    // get back to real host networking even if they come after a NetNs
+    // spawn in `depends_on` order. Kept alive for the whole function so
+    // the fd it hands out stays valid for every (re)spawn, including
+    // ones triggered by a later restart.
+    let host_netns = if !host_net_names.is_empty()
+        && containers_in_netns_count > 0
+    {
+        Some(try!(File::open("/proc/self/ns/net")
+            .map_err(|e| format!("Can't open host network namespace: {}", e))))
+    } else {
+        None
+    };
+    let host_netns_fd = host_netns.as_ref().map(|f| f.as_raw_fd());
+
+    let gwdir = network::namespace_dir();
+    let nsdir = gwdir.join("children");
+    if containers_in_netns_count > 0 {
         if !nsdir.exists() {
             try_msg!(create_dir(&nsdir, false),
                      "Failed to create dir: {err}");
@@ -123,79 +659,98 @@ pub fn run_supervise_command(config: &Config, workdir: &Path,
         try!(unshare_namespace(NewMount)
             .map_err(|e| format!("Failed to create mount namespace: {}", e)));
         try!(mount_tmpfs(&nsdir, "size=10m"));
+    }
 
-        let bridge_ns = nsdir.join("bridge");
-        let ip = try!(network::setup_bridge(&bridge_ns, &forwards));
-
-        port_forward_guard = network::PortForwardGuard::new(
-            &gwdir.join("netns"), ip, ports);
-        try!(port_forward_guard.start_forwarding());
-
-        for name in containers_in_netns.iter() {
-            let child = sup.children.get(name).unwrap();
-            let mut cmd = Command::new("/proc/self/exe");
-            cmd.arg0("vagga_wrapper");
-            cmd.keep_sigmask();
-            cmd.arg(&cmdname);
-            cmd.arg(&name);
-            common_child_command_env(&mut cmd, Some(workdir));
-            cmd.unshare(
-                [Namespace::Mount, Namespace::Ipc, Namespace::Pid]
-                .iter().cloned());
-
-            try!(set_namespace(&bridge_ns, NewNet)
-                .map_err(|e| format!("Error setting netns: {}", e)));
-            if let &BridgeCommand(_) = child {
-                // Already setup by set_namespace
-                // But also need to mount namespace_dir into container
-                cmd.env("VAGGA_NAMESPACE_DIR", &nsdir);
-            } else {
-                let netw = child.network().unwrap();
-                let net_ns;
-                let uts_ns;
-                net_ns = nsdir.join("net.".to_string() + &netw.ip);
-                uts_ns = nsdir.join("uts.".to_string() + &netw.ip);
-                // TODO(tailhook) support multiple commands with same IP
-                try!(network::setup_container(&net_ns, &uts_ns,
-                    &name, &netw.ip,
-                    &netw.hostname.as_ref().unwrap_or(name)));
-                try!(set_namespace(&net_ns, NewNet)
-                    .map_err(|e| format!("Error setting netns: {}", e)));
-                try!(set_namespace(&uts_ns, NewUts)
-                    .map_err(|e| format!("Error setting netns: {}", e)));
-            }
+    let mut trap = Trap::trap(&[SIGINT, SIGTERM, SIGCHLD, SIGUSR1]);
+    let mut children: HashMap<i32, (String, Child)> = HashMap::new();
+    let mut placements: HashMap<String, ChildPlacement> = HashMap::new();
+    let mut error = false;
+    // Kept alive until the function returns: dropping a guard tears down
+    // that network's forwarding rules.
+    let mut port_forward_guards = vec!();
+    // Lazily populated the first time a member of that network is
+    // reached in `global_order`, so a network with no ready dependents
+    // yet doesn't have its bridge stood up before it's needed.
+    let mut bridge_setup: HashMap<String, (PathBuf, String)> = HashMap::new();
 
-            match cmd.spawn() {
-                Ok(child) => { children.insert(child.pid(), (name, child)); }
-                Err(e) => {
-                    if !error {
-                        println!(
-                            "---------- \
-                            Process {} could not be run: {}. Shutting down \
-                            -----------",
-                            name, e);
-                        error = true;
-                    }
+    for name in global_order.iter() {
+        let placement = if host_net_names.contains(name) {
+            ChildPlacement::HostNet { host_netns_fd: host_netns_fd }
+        } else {
+            let net_name = net_of_child.get(name).unwrap();
+            if !bridge_setup.contains_key(net_name) {
+                let index = *net_index.get(net_name).unwrap();
+                let group = networks.get(net_name).unwrap();
+                let bridge_ns = nsdir.join(format!("bridge.{}", net_name));
+                let bridge_name = network::bridge_name_for(index);
+                // The bridge's own gateway address isn't needed here --
+                // `PortForwardGuard` forwards straight to each container's
+                // address in `group.forwards`, not to the bridge itself.
+                try!(network::setup_bridge(&bridge_ns, index));
+                if !group.internal {
+                    let mut guard = network::PortForwardGuard::new(
+                        &gwdir.join("netns"), group.forwards.clone());
+                    try!(guard.start_forwarding());
+                    port_forward_guards.push(guard);
+                }
+                bridge_setup.insert(net_name.clone(), (bridge_ns, bridge_name));
+            }
+            let &(ref bridge_ns, ref bridge_name) =
+                bridge_setup.get(net_name).unwrap();
+            ChildPlacement::NetNs {
+                bridge_ns: bridge_ns.clone(),
+                nsdir: nsdir.clone(),
+                net_name: net_name.clone(),
+                bridge_name: bridge_name.clone(),
+            }
+        };
+        placements.insert(name.clone(), placement.clone());
+        let has_dependent = depended_on.contains(name);
+        match spawn_child(&cmdname, workdir, name, sup, &placement, has_dependent) {
+            Ok(child) => { children.insert(child.pid(), (name.clone(), child)); }
+            Err(e) => {
+                if !error {
+                    println!(
+                        "---------- \
+                        Process {} could not be run: {}. Shutting down \
+                        -----------",
+                        name, e);
+                    error = true;
                 }
             }
         }
+    }
 
-        // Need to set network namespace back to bridge, to keep namespace
-        // alive. Otherwise bridge is dropped, and no connectivity between
-        // containers.
-        try!(set_namespace(&bridge_ns, NewNet)
-            .map_err(|e| format!("Error setting netns: {}", e)));
+    let control_state = Arc::new(ControlState::new());
+    {
+        let mut status = control_state.status.lock().unwrap();
+        *status = snapshot_status(&children, &placements);
+    }
+    if let Ok(path) = ::std::env::var("VAGGA_CONTROL_SOCKET") {
+        let state = control_state.clone();
+        ::std::thread::spawn(move || {
+            run_control_socket(PathBuf::from(path), state);
+        });
     }
 
     let mut errcode = 0;
     if error {
-        let mut errcode = 127;
+        errcode = 127;
         for &(_, ref child) in children.values() {
             child.signal(SIGTERM).ok();
         }
     } else {
         // Normal loop
         assert!(children.len() > 0);
+        let mut backoffs: HashMap<String, Backoff> = HashMap::new();
+        let mut disabled: HashSet<String> = HashSet::new();
+        // Restarts that are backing off: scheduled for `Instant` rather
+        // than respawned inline, so a crash-looping child's backoff delay
+        // never blocks the signal loop from noticing SIGINT/SIGTERM, other
+        // children's SIGCHLD, or control-socket commands in the meantime.
+        // A background thread wakes the loop with a self-sent SIGUSR1 once
+        // a deadline is reached; the SIGUSR1 arm is what actually respawns.
+        let mut pending_restarts: HashMap<String, Instant> = HashMap::new();
         'signal_loop: for signal in trap.by_ref() {
             match signal {
                 SIGINT => {
@@ -219,22 +774,221 @@ pub fn run_supervise_command(config: &Config, workdir: &Path,
                 SIGCHLD => {
                     for (pid, status) in reap_zombies() {
                         if let Some((name, _)) = children.remove(&pid) {
-                            errcode = convert_status(status);
-                            println!(
-                                "---------- \
-                                Process {}:{} {}. Shutting down \
-                                -----------",
-                                name, pid, status);
-                            for (pid, status) in reap_zombies() {
-                                children.remove(&pid);
+                            if disabled.contains(&name) {
+                                println!(
+                                    "---------- \
+                                    Process {}:{} {} (stopped via control \
+                                    socket) \
+                                    -----------",
+                                    name, pid, status);
+                                continue;
+                            }
+                            let should_restart = match sup.mode {
+                                stop_on_failure => false,
+                                restart_always => true,
+                                restart_on_failure => !status.success(),
+                            };
+                            if should_restart {
+                                let backoff = backoffs.entry(name.clone())
+                                    .or_insert(Backoff {
+                                        attempts: 0,
+                                        started_at: Instant::now(),
+                                    });
+                                if backoff.started_at.elapsed().as_secs()
+                                    >= BACKOFF_RESET_AFTER_SECS
+                                {
+                                    backoff.attempts = 0;
+                                }
+                                if backoff.attempts >= MAX_RESTART_ATTEMPTS {
+                                    errcode = convert_status(status);
+                                    println!(
+                                        "---------- \
+                                        Process {}:{} {} crash-looped {} \
+                                        times. Shutting down \
+                                        -----------",
+                                        name, pid, status, backoff.attempts);
+                                    for (pid, _) in reap_zombies() {
+                                        children.remove(&pid);
+                                    }
+                                    break 'signal_loop;
+                                }
+                                let delay_ms = backoff_delay_ms(
+                                    backoff.attempts);
+                                println!(
+                                    "---------- \
+                                    Process {}:{} {}. Restarting in {}ms \
+                                    (attempt {}) \
+                                    -----------",
+                                    name, pid, status, delay_ms,
+                                    backoff.attempts + 1);
+                                backoff.attempts += 1;
+                                backoff.started_at = Instant::now();
+                                pending_restarts.insert(name.clone(),
+                                    Instant::now() +
+                                        Duration::from_millis(delay_ms));
+                                let waker = getpid();
+                                ::std::thread::spawn(move || {
+                                    ::std::thread::sleep(
+                                        Duration::from_millis(delay_ms));
+                                    kill(waker, SIGUSR1).ok();
+                                });
+                            } else {
+                                errcode = convert_status(status);
+                                println!(
+                                    "---------- \
+                                    Process {}:{} {}. Shutting down \
+                                    -----------",
+                                    name, pid, status);
+                                for (pid, _) in reap_zombies() {
+                                    children.remove(&pid);
+                                }
+                                break 'signal_loop;
                             }
-                            break 'signal_loop;
                         }
                     }
-                    if children.len() == 0 {
+                    *control_state.status.lock().unwrap() =
+                        snapshot_status(&children, &placements);
+                    // A crash-looping child with a restart scheduled isn't
+                    // in `children` yet, but the loop must stay alive for
+                    // its wakeup to arrive, or it never gets respawned.
+                    if children.len() == 0 && pending_restarts.is_empty() {
                         break;
                     }
                 }
+                SIGUSR1 => {
+                    let now = Instant::now();
+                    let due: Vec<String> = pending_restarts.iter()
+                        .filter(|&(_, deadline)| *deadline <= now)
+                        .map(|(name, _)| name.clone())
+                        .collect();
+                    for name in due {
+                        pending_restarts.remove(&name);
+                        if disabled.contains(&name) {
+                            // Stopped via the control socket while its
+                            // backoff was counting down -- don't resurrect
+                            // it out from under that stop.
+                            continue;
+                        }
+                        let placement = placements.get(&name).unwrap();
+                        match spawn_child(&cmdname, workdir, &name,
+                            sup, placement, depended_on.contains(&name))
+                        {
+                            Ok(child) => {
+                                children.insert(child.pid(),
+                                    (name.clone(), child));
+                            }
+                            Err(e) => {
+                                errcode = 127;
+                                println!(
+                                    "---------- \
+                                    Process {} could not be restarted: {}. \
+                                    Shutting down \
+                                    -----------", name, e);
+                                for (pid, _) in reap_zombies() {
+                                    children.remove(&pid);
+                                }
+                                break 'signal_loop;
+                            }
+                        }
+                    }
+                    let cmds: Vec<CtlCommand> = control_state.queue.lock()
+                        .unwrap().drain(..).collect();
+                    for cmd in cmds {
+                        match cmd {
+                            CtlCommand::Stop(name, tx) => {
+                                let result = if !placements.contains_key(&name)
+                                {
+                                    Err(format!(
+                                        "unknown process: {}", name))
+                                } else {
+                                    disabled.insert(name.clone());
+                                    for &(ref n, ref child) in
+                                        children.values()
+                                    {
+                                        if *n == name {
+                                            child.signal(SIGTERM).ok();
+                                        }
+                                    }
+                                    Ok(())
+                                };
+                                tx.send(result).ok();
+                            }
+                            CtlCommand::Start(name, tx) => {
+                                let result = match placements.get(&name) {
+                                    None => Err(format!(
+                                        "unknown process: {}", name)),
+                                    Some(placement) => {
+                                        disabled.remove(&name);
+                                        // Spawning it now pre-empts
+                                        // whatever backoff it was counting
+                                        // down under; drop that entry so
+                                        // the backoff thread's later
+                                        // wakeup doesn't spawn a second
+                                        // copy.
+                                        pending_restarts.remove(&name);
+                                        let running = children.values()
+                                            .any(|&(ref n, _)| *n == name);
+                                        if running {
+                                            Ok(())
+                                        } else {
+                                            match spawn_child(&cmdname,
+                                                workdir, &name, sup,
+                                                placement,
+                                                depended_on.contains(&name))
+                                            {
+                                                Ok(child) => {
+                                                    children.insert(
+                                                        child.pid(),
+                                                        (name.clone(),
+                                                            child));
+                                                    Ok(())
+                                                }
+                                                Err(e) => Err(e),
+                                            }
+                                        }
+                                    }
+                                };
+                                tx.send(result).ok();
+                            }
+                            CtlCommand::Restart(name, tx) => {
+                                let result = match placements.get(&name) {
+                                    None => Err(format!(
+                                        "unknown process: {}", name)),
+                                    Some(placement) => {
+                                        disabled.remove(&name);
+                                        pending_restarts.remove(&name);
+                                        let old_pid = children.iter()
+                                            .find(|&(_, &(ref n, _))|
+                                                *n == name)
+                                            .map(|(&pid, _)| pid);
+                                        if let Some(pid) = old_pid {
+                                            if let Some((_, child)) =
+                                                children.get(&pid)
+                                            {
+                                                child.signal(SIGTERM).ok();
+                                            }
+                                            children.remove(&pid);
+                                        }
+                                        match spawn_child(&cmdname, workdir,
+                                            &name, sup, placement,
+                                            depended_on.contains(&name))
+                                        {
+                                            Ok(child) => {
+                                                children.insert(child.pid(),
+                                                    (name.clone(), child));
+                                                Ok(())
+                                            }
+                                            Err(e) => Err(e),
+                                        }
+                                    }
+                                };
+                                tx.send(result).ok();
+                            }
+                        }
+                    }
+                    *control_state.status.lock().unwrap() =
+                        snapshot_status(&children, &placements);
+                }
                 _ => unreachable!(),
             }
         }
@@ -258,6 +1012,11 @@ pub fn run_supervise_command(config: &Config, workdir: &Path,
                         break;
                     }
                 }
+                SIGUSR1 => {
+                    // Shutting down already; drop any queued control
+                    // commands instead of acting on them.
+                    control_state.queue.lock().unwrap().clear();
+                }
                 _ => unreachable!(),
             }
         }
@@ -265,3 +1024,98 @@ pub fn run_supervise_command(config: &Config, workdir: &Path,
 
     Ok(errcode)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::{backoff_delay_ms, detect_cycle, parse_ctl_line, toposort,
+        BASE_BACKOFF_MS, MAX_BACKOFF_MS};
+
+    fn deps(pairs: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        pairs.iter().map(|&(name, ds)| {
+            (name.to_string(), ds.iter().map(|d| d.to_string()).collect())
+        }).collect()
+    }
+
+    fn names(ns: &[&str]) -> Vec<String> {
+        ns.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn toposort_orders_dependencies_before_dependents() {
+        let d = deps(&[("web", &["db"]), ("db", &[])]);
+        let order = toposort(&d, &names(&["web", "db"])).unwrap();
+        assert_eq!(order, vec!("db".to_string(), "web".to_string()));
+    }
+
+    #[test]
+    fn toposort_skips_a_known_but_unselected_dependency() {
+        // "web" depends on "db", but "db" was excluded from this run;
+        // ordering "web" alone must not error or try to place "db".
+        let d = deps(&[("web", &["db"]), ("db", &[])]);
+        let order = toposort(&d, &names(&["web"])).unwrap();
+        assert_eq!(order, vec!("web".to_string()));
+    }
+
+    #[test]
+    fn toposort_rejects_dependency_on_unknown_process() {
+        let d = deps(&[("web", &["ghost"])]);
+        assert!(toposort(&d, &names(&["web"])).is_err());
+    }
+
+    #[test]
+    fn toposort_rejects_a_cycle_in_the_selected_set() {
+        let d = deps(&[("a", &["b"]), ("b", &["a"])]);
+        assert!(toposort(&d, &names(&["a", "b"])).is_err());
+    }
+
+    #[test]
+    fn detect_cycle_passes_an_acyclic_graph() {
+        let d = deps(&[("web", &["db"]), ("db", &[])]);
+        assert!(detect_cycle(&d).is_ok());
+    }
+
+    #[test]
+    fn detect_cycle_catches_a_cycle_among_excluded_children() {
+        // Neither "a" nor "b" need be in any run's selected set for this
+        // to be a config error -- unlike `toposort`, `detect_cycle` always
+        // walks every known name.
+        let d = deps(&[("a", &["b"]), ("b", &["a"]), ("unrelated", &[])]);
+        assert!(detect_cycle(&d).is_err());
+    }
+
+    #[test]
+    fn detect_cycle_rejects_dependency_on_unknown_process() {
+        let d = deps(&[("web", &["ghost"])]);
+        assert!(detect_cycle(&d).is_err());
+    }
+
+    #[test]
+    fn backoff_delay_doubles_each_attempt() {
+        assert_eq!(backoff_delay_ms(0), BASE_BACKOFF_MS);
+        assert_eq!(backoff_delay_ms(1), BASE_BACKOFF_MS * 2);
+        assert_eq!(backoff_delay_ms(2), BASE_BACKOFF_MS * 4);
+    }
+
+    #[test]
+    fn backoff_delay_caps_at_max() {
+        assert_eq!(backoff_delay_ms(6), MAX_BACKOFF_MS);
+        assert_eq!(backoff_delay_ms(100), MAX_BACKOFF_MS);
+    }
+
+    #[test]
+    fn parse_ctl_line_splits_verb_and_arg() {
+        assert_eq!(parse_ctl_line("stop foo"),
+            ("stop".to_string(), "foo".to_string()));
+        assert_eq!(parse_ctl_line("restart  db \n"),
+            ("restart".to_string(), "db".to_string()));
+    }
+
+    #[test]
+    fn parse_ctl_line_handles_no_arg() {
+        assert_eq!(parse_ctl_line("status"),
+            ("status".to_string(), "".to_string()));
+        assert_eq!(parse_ctl_line(""),
+            ("".to_string(), "".to_string()));
+    }
+}
@@ -0,0 +1,38 @@
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+pub mod pid1;
+
+/// Runs `argv` as the real supervised command under `pid1::run_as_pid1`:
+/// the calling process (pid 1 of the pid namespace
+/// `supervisor::spawn_child` unshared) forks, the forked child execs
+/// `argv`, and the caller stays at pid 1 reaping the grandchildren a
+/// forking command leaves behind.
+///
+/// Nothing in this source tree calls this function. `vagga_wrapper`'s own
+/// `main()` -- which would parse `<cmdname> <name>`, look the child back
+/// up in the config and call this -- isn't part of this source tree
+/// either, so as this tree stands, `spawn_child`'s `unshare(Namespace::Pid)`
+/// is not actually paired with a reaper: the zombie-accumulation problem
+/// described above is not fixed by this function existing, only by
+/// something calling it.
+pub fn run_wrapped_command(workdir: &Path, argv: Vec<String>) -> ! {
+    let workdir = workdir.to_path_buf();
+    pid1::run_as_pid1(move || exec_argv(&workdir, &argv))
+}
+
+/// Replaces the current (forked) process image with `argv[0]`, returning
+/// only on failure -- `run_as_pid1` treats a returned `io::Error` as "the
+/// exec never happened".
+fn exec_argv(workdir: &Path, argv: &Vec<String>) -> io::Error {
+    if argv.is_empty() {
+        return io::Error::new(io::ErrorKind::InvalidInput,
+            "No command to run");
+    }
+    Command::new(&argv[0])
+        .args(&argv[1..])
+        .current_dir(workdir)
+        .exec()
+}
@@ -0,0 +1,87 @@
+use std::io;
+use std::process::exit;
+
+use libc::{pid_t, c_int};
+use nix::sys::signal::{SIGTERM, SIGINT, SIGKILL, Signal, kill};
+use nix::sys::wait::{waitpid, WaitStatus, WNOHANG};
+use nix::unistd::{fork, ForkResult};
+
+
+/// `vagga_wrapper` becomes pid 1 of a fresh pid namespace as soon as the
+/// supervisor's `unshare(Namespace::Pid)` takes effect.  If the command it
+/// runs forks and exits before its own children do, those grandchildren
+/// are reparented to us -- and if we're just execing the real command
+/// directly, nobody is left to reap them; they pile up as zombies until
+/// the whole namespace is torn down.
+///
+/// Adopting youki's double-fork shape fixes this: we fork once more, let
+/// the grandchild exec the real command, and keep the pid-1 slot for a
+/// small reaper loop that waits for *any* child (the real command or
+/// anything it orphaned), forwards `SIGTERM`/`SIGINT` to the direct
+/// child, and exits with that child's status once it's gone.
+///
+/// `run_child` execs (or otherwise becomes) the real command and never
+/// returns on success.
+pub fn run_as_pid1<F>(run_child: F) -> !
+    where F: FnOnce() -> io::Error
+{
+    match fork() {
+        Ok(ForkResult::Child) => {
+            let err = run_child();
+            // only reached if exec/spawn failed
+            println!("Error running command: {}", err);
+            exit(127);
+        }
+        Ok(ForkResult::Parent { child }) => {
+            reap_until_child_exits(child);
+        }
+        Err(e) => {
+            println!("Error forking pid 1 child: {}", e);
+            exit(127);
+        }
+    }
+}
+
+fn forward(sig: Signal, child: pid_t) {
+    kill(child, sig).ok();
+}
+
+/// The actual pid-1 reaper: on each `SIGCHLD` it calls
+/// `waitpid(-1, WNOHANG)` until nothing is left to reap, so it picks up
+/// status for every descendant, not just `child`, but only exits (with
+/// `child`'s converted status) once `child` itself is gone.
+fn reap_until_child_exits(child: pid_t) -> ! {
+    use signal::trap::Trap;
+    use nix::sys::signal::SIGCHLD;
+
+    let mut trap = Trap::trap(&[SIGINT, SIGTERM, SIGCHLD]);
+    for signal in trap.by_ref() {
+        match signal {
+            SIGINT => forward(SIGINT, child),
+            SIGTERM => forward(SIGTERM, child),
+            SIGCHLD => {
+                loop {
+                    match waitpid(-1, Some(WNOHANG)) {
+                        Ok(WaitStatus::StillAlive) => break,
+                        Ok(WaitStatus::Exited(pid, code)) if pid == child => {
+                            exit(code);
+                        }
+                        Ok(WaitStatus::Signaled(pid, sig, _))
+                            if pid == child =>
+                        {
+                            exit(128 + sig as c_int);
+                        }
+                        Ok(_) => continue, // a reparented grandchild; reaped
+                        Err(_) => break,
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    // Trap was dropped (shouldn't normally happen); make sure the child
+    // isn't left behind.
+    forward(SIGKILL, child);
+    let _ = waitpid(child, None);
+    exit(127)
+}